@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate approx;
+
+pub extern crate uuid;
+
+pub mod ob;
+
+pub use ob::OrderBook;
+pub use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BookRecord {
+    pub price: f64,
+    pub size: f64,
+    pub id: Uuid,
+    /// good-til-date expiry; `None` means good-til-cancelled
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MatchUuid,
+    /// a market order could not be fully filled against the resting book;
+    /// carries whatever fills were produced before liquidity ran out, so no
+    /// executed trade is ever dropped on the floor
+    Unfilled(Vec<Fill>),
+    /// price is not an integer multiple of the book's `tick_size`
+    InvalidTick,
+    /// size is not an integer multiple of the book's `lot_size`
+    InvalidLot,
+    /// size is smaller than the book's `min_size`
+    BelowMinSize,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// per-instrument book parameters, modeled on DeepBook's `Book` params
+#[derive(Debug, Clone, Copy)]
+pub struct OrderBookConfig {
+    /// smallest allowed price increment
+    pub tick_size: f64,
+    /// smallest allowed size increment
+    pub lot_size: f64,
+    /// smallest allowed order size
+    pub min_size: f64,
+}
+
+/// an incoming order submitted to the matching engine, modeled on the
+/// order kinds accepted by the `lobster` matching engine
+#[derive(Debug, Clone, Copy)]
+pub enum OrderType {
+    Market { id: Uuid, side: Side, qty: f64 },
+    Limit { id: Uuid, side: Side, price: f64, qty: f64 },
+}
+
+/// a maker order submitted to `OrderBook::open`, modeled on mango-v4's
+/// `BookSideOrderTree::OraclePegged`: either resting at a fixed price, or
+/// pegged so its effective price tracks `oracle_price + offset`
+#[derive(Debug, Clone, Copy)]
+pub enum OpenOrder {
+    Fixed(BookRecord),
+    Pegged { id: Uuid, size: f64, offset: f64, expires_at: Option<u64> },
+}
+
+/// a single maker/taker match produced by `OrderBook::submit`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fill {
+    pub maker_id: Uuid,
+    pub taker_id: Uuid,
+    pub price: f64,
+    pub size: f64,
+}
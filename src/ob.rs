@@ -2,108 +2,285 @@
 use super::uuid::Uuid;
 
 use std;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt;
-use std::ops::RangeInclusive;
-use super::{Side, BookRecord, Error, Result};
+use super::{Side, BookRecord, Error, Result, OrderType, OpenOrder, Fill, OrderBookConfig};
 
+/// `(size, id, expires_at)` for a single resting order within a price level
+type BookEntry = (f64, Uuid, Option<u64>);
+
+/// whether an order's time-in-force has lapsed as of `now_ts`
+fn is_expired(expires_at: Option<u64>, now_ts: u64) -> bool {
+    expires_at.map_or(false, |exp| exp <= now_ts)
+}
+
+/// a resting order whose effective price tracks `oracle_price + offset`,
+/// modeled on mango-v4's `BookSideOrderTree::OraclePegged`
+#[derive(Debug, Clone, Copy)]
+struct PegOrder {
+    side: Side,
+    id: Uuid,
+    size: f64,
+    offset: f64,
+    expires_at: Option<u64>,
+}
 
 /// main OrderBook structure
 pub struct OrderBook {
-    pub book: Vec<VecDeque<(f64, Uuid)>>,
+    pub book: BTreeMap<usize, VecDeque<BookEntry>>,
     bid: usize,
     ask: usize,
-    _match: usize
+    _match: usize,
+    tick_size: f64,
+    lot_size: f64,
+    min_size: f64,
+    /// timestamp passed by the last call that took a `now_ts`, used to skip
+    /// expired orders from reads that have no `now_ts` of their own
+    now_ts: u64,
+    /// source of truth for pegged orders, independent of the current oracle
+    pegs: Vec<PegOrder>,
+    /// pegged orders re-projected onto the fixed tick grid at the last
+    /// `set_oracle` call
+    peg_book: BTreeMap<usize, VecDeque<BookEntry>>,
+    peg_bid: usize,
+    peg_ask: usize,
+    oracle_price: Option<f64>,
 }
 
 impl OrderBook {
-    /// creates new orderbook
-    pub fn new() -> Self {
+    /// creates new orderbook for an instrument with the given tick/lot/min size
+    pub fn new(config: OrderBookConfig) -> Self {
         Self {
-            book: vec![VecDeque::new(); super::MAX_SIZE],
+            book: BTreeMap::new(),
             bid: std::usize::MIN,
             ask: std::usize::MAX,
-            _match: 0
+            _match: 0,
+            tick_size: config.tick_size,
+            lot_size: config.lot_size,
+            min_size: config.min_size,
+            now_ts: 0,
+            pegs: Vec::new(),
+            peg_book: BTreeMap::new(),
+            peg_bid: std::usize::MIN,
+            peg_ask: std::usize::MAX,
+            oracle_price: None,
         }
     }
 
-    /// get current bid
+    /// get current bid, merging the fixed and oracle-pegged trees
     pub fn bid(&self) -> f64 {
-        self.bid as f64 / 100.0
+        self.merged_bid() as f64 * self.tick_size
     }
 
-    /// get current ask
+    /// get current ask, merging the fixed and oracle-pegged trees
     pub fn ask(&self) -> f64 {
-        self.ask as f64 / 100.0
+        self.merged_ask() as f64 * self.tick_size
     }
 
     pub fn __match(&self) -> f64 {
-        self._match as f64 / 100.0
+        self._match as f64 * self.tick_size
     }
 
-    fn side(&self, range: RangeInclusive<usize>) -> Vec<f64> {
-        self.book[range].iter()
-            .map(|x| x.iter().map(|x| x.0).sum())
-            .collect::<Vec<_>>()
+    fn merged_bid(&self) -> usize {
+        self.bid.max(self.peg_bid)
     }
 
-    /// get size of top sz bids (includes empty)
+    fn merged_ask(&self) -> usize {
+        self.ask.min(self.peg_ask)
+    }
+
+    /// reject a price that is not an integer multiple of `tick_size`
+    fn validate_tick(&self, price: f64) -> Result<()> {
+        let ticks = price / self.tick_size;
+        if relative_eq!(ticks, ticks.round()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidTick)
+        }
+    }
+
+    /// reject a size that is below `min_size` or not a multiple of `lot_size`
+    fn validate_size(&self, size: f64) -> Result<()> {
+        if size < self.min_size && !relative_eq!(size, self.min_size) {
+            return Err(Error::BelowMinSize);
+        }
+        let lots = size / self.lot_size;
+        if relative_eq!(lots, lots.round()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidLot)
+        }
+    }
+
+    /// get size of the top sz occupied bid levels, best first, merging the
+    /// fixed and oracle-pegged trees and skipping orders expired as of the
+    /// last touch
     pub fn bids(&self, sz: usize) -> Vec<f64> {
-        self.side((self.bid-sz+1)..=self.bid)
+        let best = self.merged_bid();
+        let idxs: BTreeSet<usize> = self.book.range(..=best).map(|(&idx, _)| idx)
+            .chain(self.peg_book.range(..=best).map(|(&idx, _)| idx))
+            .collect();
+        idxs.into_iter().rev().take(sz).map(|idx| self.merged_level_volume(idx)).collect()
     }
 
-    /// get size of low sz bids (includes empty)
+    /// get size of the top sz occupied ask levels, best first, merging the
+    /// fixed and oracle-pegged trees and skipping orders expired as of the
+    /// last touch
     pub fn asks(&self, sz: usize) -> Vec<f64> {
-        self.side(self.ask..=self.ask+sz-1)
+        let best = self.merged_ask();
+        let idxs: BTreeSet<usize> = self.book.range(best..).map(|(&idx, _)| idx)
+            .chain(self.peg_book.range(best..).map(|(&idx, _)| idx))
+            .collect();
+        idxs.into_iter().take(sz).map(|idx| self.merged_level_volume(idx)).collect()
+    }
+
+    fn level_volume(&self, lvl: &VecDeque<BookEntry>) -> f64 {
+        lvl.iter()
+            .filter(|&&(_, _, expires_at)| !is_expired(expires_at, self.now_ts))
+            .map(|x| x.0)
+            .sum()
+    }
+
+    /// combined resting size of the fixed and pegged levels at `idx`
+    fn merged_level_volume(&self, idx: usize) -> f64 {
+        let fixed = self.book.get(&idx).map_or(0.0, |lvl| self.level_volume(lvl));
+        let pegged = self.peg_book.get(&idx).map_or(0.0, |lvl| self.level_volume(lvl));
+        fixed + pegged
+    }
+
+    /// yield unexpired `(price, size, id)` entries resting on `side`, merging
+    /// the fixed and oracle-pegged trees, from best bid down when `side` is
+    /// `Buy` or best ask up when `Sell`; at a tied price the fixed tree is
+    /// yielded before the pegged tree
+    pub fn iter_valid(&self, side: Side, now_ts: u64) -> impl Iterator<Item = (f64, f64, Uuid)> + '_ {
+        let idxs: Vec<usize> = match side {
+            Side::Buy => {
+                let best = self.merged_bid();
+                let set: BTreeSet<usize> = self.book.range(..=best).map(|(&idx, _)| idx)
+                    .chain(self.peg_book.range(..=best).map(|(&idx, _)| idx))
+                    .collect();
+                set.into_iter().rev().collect()
+            }
+            Side::Sell => {
+                let best = self.merged_ask();
+                let set: BTreeSet<usize> = self.book.range(best..).map(|(&idx, _)| idx)
+                    .chain(self.peg_book.range(best..).map(|(&idx, _)| idx))
+                    .collect();
+                set.into_iter().collect()
+            }
+        };
+        idxs.into_iter().flat_map(move |idx| {
+            let price = idx as f64 * self.tick_size;
+            let fixed = self.book.get(&idx).into_iter().flatten();
+            let pegged = self.peg_book.get(&idx).into_iter().flatten();
+            fixed.chain(pegged)
+                .filter(move |&&(_, _, expires_at)| !is_expired(expires_at, now_ts))
+                .map(move |&(size, id, _)| (price, size, id))
+        })
     }
 
     /// reload OrderBook from full bids and asks L3
-    pub fn reload(&mut self, bids: Vec<BookRecord>, asks: Vec<BookRecord>) -> Result<()> {
+    pub fn reload(&mut self, bids: Vec<BookRecord>, asks: Vec<BookRecord>, now_ts: u64) -> Result<()> {
         self.bid = std::usize::MIN;
         self.ask = std::usize::MAX;
-        self.book.iter_mut().map(|x| *x = VecDeque::new()).count();
+        self.book.clear();
 
         bids.into_iter()
-            .try_for_each(|rec| self.open(Side::Buy, rec))?;
+            .try_for_each(|rec| self.open(Side::Buy, OpenOrder::Fixed(rec), now_ts))?;
         asks.into_iter()
-            .try_for_each(|rec| self.open(Side::Sell, rec))?;
+            .try_for_each(|rec| self.open(Side::Sell, OpenOrder::Fixed(rec), now_ts))?;
         Ok(())
     }
 
     fn get_idx(&self, price: f64) -> Result<usize> {
-        let p_idx = (price * 100.0) as usize;
-        if p_idx >= self.book.len() {
-            Err(Error::Range)
-        } else {
-            Ok(p_idx)
+        self.validate_tick(price)?;
+        Ok((price / self.tick_size).round() as usize)
+    }
+
+    /// open an order, either resting at a fixed price or pegged to the oracle
+    pub fn open(&mut self, side: Side, order: OpenOrder, now_ts: u64) -> Result<()> {
+        self.now_ts = now_ts;
+        match order {
+            OpenOrder::Fixed(rec) => self.open_fixed(side, rec, now_ts),
+            OpenOrder::Pegged { id, size, offset, expires_at } => {
+                self.open_pegged(side, id, size, offset, expires_at, now_ts)
+            }
         }
     }
 
-    /// open order
-    pub fn open(&mut self, side: Side, rec: BookRecord) -> Result<()> {
+    fn open_fixed(&mut self, side: Side, rec: BookRecord, now_ts: u64) -> Result<()> {
+        self.validate_size(rec.size)?;
         let p_idx = self.get_idx(rec.price)?;
+        self.check_ask_bid(p_idx, now_ts);
         match side {
             Side::Buy if p_idx > self.bid => self.bid = p_idx,
             Side::Sell if p_idx < self.ask => self.ask = p_idx,
             _ => (),
         }
         assert!(self.bid < self.ask);
-        self.book[p_idx].push_back((rec.size, rec.id));
+        self.book.entry(p_idx).or_insert_with(VecDeque::new).push_back((rec.size, rec.id, rec.expires_at));
+        Ok(())
+    }
+
+    fn open_pegged(&mut self, side: Side, id: Uuid, size: f64, offset: f64, expires_at: Option<u64>, now_ts: u64) -> Result<()> {
+        self.validate_size(size)?;
+        if let Some(oracle_price) = self.oracle_price {
+            self.validate_tick(oracle_price + offset)?;
+        }
+        self.pegs.push(PegOrder { side, id, size, offset, expires_at });
+        if let Some(oracle_price) = self.oracle_price {
+            let peg_idx = self.pegs.len() - 1;
+            self.project_peg(peg_idx, oracle_price, now_ts)?;
+        }
+        Ok(())
+    }
+
+    /// set the oracle reference price and re-project every unexpired pegged
+    /// order onto the fixed tick grid before the next bid/ask/matching read;
+    /// a peg whose `oracle_price + offset` is off the tick grid is left out
+    /// of the peg tree until a later oracle price puts it back on the grid
+    pub fn set_oracle(&mut self, price: f64) {
+        self.oracle_price = Some(price);
+        self.peg_book.clear();
+        self.peg_bid = std::usize::MIN;
+        self.peg_ask = std::usize::MAX;
+
+        let now_ts = self.now_ts;
+        self.pegs.retain(|peg| !is_expired(peg.expires_at, now_ts));
+        for peg_idx in 0..self.pegs.len() {
+            self.project_peg(peg_idx, price, now_ts).unwrap_or_default();
+        }
+    }
+
+    /// insert `self.pegs[peg_idx]` into the peg tree at `oracle_price + offset`
+    fn project_peg(&mut self, peg_idx: usize, oracle_price: f64, now_ts: u64) -> Result<()> {
+        let peg = self.pegs[peg_idx];
+        if is_expired(peg.expires_at, now_ts) {
+            return Ok(());
+        }
+        let p_idx = self.get_idx(oracle_price + peg.offset)?;
+        match peg.side {
+            Side::Buy if p_idx > self.peg_bid => self.peg_bid = p_idx,
+            Side::Sell if p_idx < self.peg_ask => self.peg_ask = p_idx,
+            _ => (),
+        }
+        self.peg_book.entry(p_idx).or_insert_with(VecDeque::new).push_back((peg.size, peg.id, peg.expires_at));
         Ok(())
     }
 
     /// match order
     pub fn _match(&mut self, price: f64, size: f64, id: Uuid) -> Result<()> {
         let p_idx = self.get_idx(price)?;
+        let level = self.book.get_mut(&p_idx).ok_or(Error::MatchUuid)?;
 
-        if self.book[p_idx].is_empty() || id != self.book[p_idx][0].1 {
+        if level.is_empty() || id != level[0].1 {
             return Err(Error::MatchUuid);
         }
-        let mut sz = self.book[p_idx][0].0;
+        let mut sz = level[0].0;
         sz -= size;
         if relative_eq!(sz, 0.0) {
-            self.book[p_idx].pop_front();
-            self.check_ask_bid(p_idx);
+            level.pop_front();
+            self.check_ask_bid(p_idx, self.now_ts);
             self._match = p_idx;
         }
         Ok(())
@@ -111,32 +288,37 @@ impl OrderBook {
 
     pub fn test_match(&mut self, price: f64) -> Result<bool> {
         let p_idx = self.get_idx(price)?;
-        if self.book[p_idx].is_empty() {
+        let level = self.book.get_mut(&p_idx).ok_or(Error::MatchUuid)?;
+        if level.is_empty() {
             return Err(Error::MatchUuid);
         }
-        if Uuid::nil() != self.book[p_idx][0].1 {
+        if Uuid::nil() != level[0].1 {
             return Ok(false);
         }
-        self.book[p_idx].pop_front();
-        self.check_ask_bid(p_idx);
+        level.pop_front();
+        self.check_ask_bid(p_idx, self.now_ts);
         Ok(true)
     }
 
     /// done order
-    pub fn done(&mut self, price: f64, id: Uuid) -> Result<()> {
+    pub fn done(&mut self, price: f64, id: Uuid, now_ts: u64) -> Result<()> {
         let p_idx = self.get_idx(price)?;
-        self.book[p_idx].retain(|&(_, it_id)| it_id != id);
-        self.check_ask_bid(p_idx);
+        self.now_ts = now_ts;
+        if let Some(level) = self.book.get_mut(&p_idx) {
+            level.retain(|&(_, it_id, _)| it_id != id);
+        }
+        self.check_ask_bid(p_idx, now_ts);
         Ok(())
     }
 
     /// change order
-    pub fn change(&mut self, price: f64, new_size: f64, id: Uuid) -> Result<()> {
+    pub fn change(&mut self, price: f64, new_size: f64, id: Uuid, now_ts: u64) -> Result<()> {
         let p_idx = self.get_idx(price)?;
+        self.now_ts = now_ts;
         if new_size == 0.0 {
-            self.done(price, id).unwrap_or_default();
-        } else {
-            self.book[p_idx].iter_mut().for_each(|(it_size, it_id)| {
+            self.done(price, id, now_ts).unwrap_or_default();
+        } else if let Some(level) = self.book.get_mut(&p_idx) {
+            level.iter_mut().for_each(|(it_size, it_id, _)| {
                 if *it_id == id {
                     *it_size = new_size;
                 }
@@ -145,31 +327,300 @@ impl OrderBook {
         Ok(())
     }
 
-    fn check_ask_bid(&mut self, p_idx: usize) {
-        if p_idx == self.bid {
-            while self.book[self.bid].len() == 0 {
-                self.bid -= 1;
+    /// submit an order to the crossing engine, matching it against the
+    /// resting book before (for limit orders) resting any remainder
+    pub fn submit(&mut self, order: OrderType, now_ts: u64) -> Result<Vec<Fill>> {
+        self.now_ts = now_ts;
+        match order {
+            OrderType::Limit { id, side, price, qty } => {
+                self.validate_size(qty)?;
+                self.submit_limit(id, side, price, qty, now_ts)
+            }
+            OrderType::Market { id, side, qty } => {
+                self.validate_size(qty)?;
+                self.submit_market(id, side, qty, now_ts)
             }
         }
+    }
+
+    fn submit_limit(&mut self, id: Uuid, side: Side, price: f64, qty: f64, now_ts: u64) -> Result<Vec<Fill>> {
+        let p_idx = self.get_idx(price)?;
+        let fills = match side {
+            Side::Buy => self.cross_asks(id, qty, Some(p_idx), now_ts),
+            Side::Sell => self.cross_bids(id, qty, Some(p_idx), now_ts),
+        };
+        let filled: f64 = fills.iter().map(|fill| fill.size).sum();
+        let remaining = qty - filled;
+        if remaining > 0.0 && !relative_eq!(remaining, 0.0) {
+            self.open(side, OpenOrder::Fixed(BookRecord { price, size: remaining, id, expires_at: None }), now_ts)?;
+        }
+        Ok(fills)
+    }
+
+    fn submit_market(&mut self, id: Uuid, side: Side, qty: f64, now_ts: u64) -> Result<Vec<Fill>> {
+        let fills = match side {
+            Side::Buy => self.cross_asks(id, qty, None, now_ts),
+            Side::Sell => self.cross_bids(id, qty, None, now_ts),
+        };
+        let filled: f64 = fills.iter().map(|fill| fill.size).sum();
+        if filled + f64::EPSILON < qty && !relative_eq!(filled, qty) {
+            return Err(Error::Unfilled(fills));
+        }
+        Ok(fills)
+    }
+
+    /// walk ask levels from the best ask upward, filling `qty` against
+    /// resting sell orders from the fixed tree and the oracle-pegged tree
+    /// merged by price; `limit` caps how high the walk may cross. expired
+    /// makers are dropped from the book instead of filled. at a tied price
+    /// the fixed tree has time priority over pegged orders
+    fn cross_asks(&mut self, taker_id: Uuid, mut qty: f64, limit: Option<usize>, now_ts: u64) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        while qty > 0.0 {
+            let best = self.ask.min(self.peg_ask);
+            if best == std::usize::MAX {
+                break;
+            }
+            if let Some(limit) = limit {
+                if best > limit {
+                    break;
+                }
+            }
+            let price = best as f64 * self.tick_size;
+            let from_fixed = best == self.ask;
+            let level = if from_fixed {
+                self.book.get_mut(&best).expect("ask always points to an occupied level")
+            } else {
+                self.peg_book.get_mut(&best).expect("peg ask always points to an occupied level")
+            };
+            let (maker_size, maker_id, expires_at) = level[0];
+            if is_expired(expires_at, now_ts) {
+                level.pop_front();
+            } else if maker_size <= qty {
+                level.pop_front();
+                qty -= maker_size;
+                fills.push(Fill { maker_id, taker_id, price, size: maker_size });
+            } else {
+                level[0].0 -= qty;
+                fills.push(Fill { maker_id, taker_id, price, size: qty });
+                qty = 0.0;
+            }
+            if from_fixed {
+                self.check_ask_bid(best, now_ts);
+            } else {
+                self.check_peg_ask_bid(best, now_ts);
+            }
+        }
+        fills
+    }
+
+    /// walk bid levels from the best bid downward, filling `qty` against
+    /// resting buy orders from the fixed tree and the oracle-pegged tree
+    /// merged by price; `limit` floors how low the walk may cross. expired
+    /// makers are dropped from the book instead of filled. at a tied price
+    /// the fixed tree has time priority over pegged orders
+    fn cross_bids(&mut self, taker_id: Uuid, mut qty: f64, limit: Option<usize>, now_ts: u64) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        while qty > 0.0 {
+            let best = self.bid.max(self.peg_bid);
+            if best == std::usize::MIN {
+                break;
+            }
+            if let Some(limit) = limit {
+                if best < limit {
+                    break;
+                }
+            }
+            let price = best as f64 * self.tick_size;
+            let from_fixed = best == self.bid;
+            let level = if from_fixed {
+                self.book.get_mut(&best).expect("bid always points to an occupied level")
+            } else {
+                self.peg_book.get_mut(&best).expect("peg bid always points to an occupied level")
+            };
+            let (maker_size, maker_id, expires_at) = level[0];
+            if is_expired(expires_at, now_ts) {
+                level.pop_front();
+            } else if maker_size <= qty {
+                level.pop_front();
+                qty -= maker_size;
+                fills.push(Fill { maker_id, taker_id, price, size: maker_size });
+            } else {
+                level[0].0 -= qty;
+                fills.push(Fill { maker_id, taker_id, price, size: qty });
+                qty = 0.0;
+            }
+            if from_fixed {
+                self.check_ask_bid(best, now_ts);
+            } else {
+                self.check_peg_ask_bid(best, now_ts);
+            }
+        }
+        fills
+    }
+
+    /// prune expired orders and an emptied level, then, if it was the
+    /// touched bid/ask, advance to the next occupied neighbor
+    fn check_ask_bid(&mut self, p_idx: usize, now_ts: u64) {
+        if let Some(lvl) = self.book.get_mut(&p_idx) {
+            lvl.retain(|&(_, _, expires_at)| !is_expired(expires_at, now_ts));
+        }
+        if self.book.get(&p_idx).map_or(false, |lvl| lvl.is_empty()) {
+            self.book.remove(&p_idx);
+        }
+
+        if p_idx == self.bid {
+            self.bid = self.book.range(..=p_idx).next_back()
+                .map(|(&idx, _)| idx)
+                .unwrap_or(std::usize::MIN);
+        }
 
         if p_idx == self.ask {
-            while self.book[self.ask].len() == 0 {
-                self.ask += 1;
+            self.ask = self.book.range(p_idx..).next()
+                .map(|(&idx, _)| idx)
+                .unwrap_or(std::usize::MAX);
+        }
+    }
+
+    /// same as `check_ask_bid`, but for the oracle-pegged tree
+    fn check_peg_ask_bid(&mut self, p_idx: usize, now_ts: u64) {
+        if let Some(lvl) = self.peg_book.get_mut(&p_idx) {
+            lvl.retain(|&(_, _, expires_at)| !is_expired(expires_at, now_ts));
+        }
+        if self.peg_book.get(&p_idx).map_or(false, |lvl| lvl.is_empty()) {
+            self.peg_book.remove(&p_idx);
+        }
+
+        if p_idx == self.peg_bid {
+            self.peg_bid = self.peg_book.range(..=p_idx).next_back()
+                .map(|(&idx, _)| idx)
+                .unwrap_or(std::usize::MIN);
+        }
+
+        if p_idx == self.peg_ask {
+            self.peg_ask = self.peg_book.range(p_idx..).next()
+                .map(|(&idx, _)| idx)
+                .unwrap_or(std::usize::MAX);
+        }
+    }
+}
+
+/// best-of-book queries, modeled on the `baseline` crate's `TopOfBook` trait
+pub trait TopOfBook {
+    fn bid_price(&self) -> Option<f64>;
+    fn ask_price(&self) -> Option<f64>;
+    fn bid_volume(&self) -> Option<f64>;
+    fn ask_volume(&self) -> Option<f64>;
+    /// `(bid + ask) / 2`, `None` if the book is one-sided or empty
+    fn mid_price(&self) -> Option<f64>;
+    /// `ask - bid`, `None` if the book is one-sided or empty
+    fn spread(&self) -> Option<f64>;
+    /// volume-weighted average price a taker on the given side would
+    /// pay/receive filling `size` against the resting book (`Buy` walks the
+    /// asks, `Sell` walks the bids)
+    fn vwap(&self, side: Side, size: f64) -> Option<f64>;
+}
+
+impl TopOfBook for OrderBook {
+    fn bid_price(&self) -> Option<f64> {
+        let idx = self.merged_bid();
+        if idx == std::usize::MIN {
+            None
+        } else {
+            Some(idx as f64 * self.tick_size)
+        }
+    }
+
+    fn ask_price(&self) -> Option<f64> {
+        let idx = self.merged_ask();
+        if idx == std::usize::MAX {
+            None
+        } else {
+            Some(idx as f64 * self.tick_size)
+        }
+    }
+
+    fn bid_volume(&self) -> Option<f64> {
+        let idx = self.merged_bid();
+        if idx == std::usize::MIN {
+            None
+        } else {
+            Some(self.merged_level_volume(idx))
+        }
+    }
+
+    fn ask_volume(&self) -> Option<f64> {
+        let idx = self.merged_ask();
+        if idx == std::usize::MAX {
+            None
+        } else {
+            Some(self.merged_level_volume(idx))
+        }
+    }
+
+    fn mid_price(&self) -> Option<f64> {
+        match (self.bid_price(), self.ask_price()) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / 2.0),
+            _ => None,
+        }
+    }
+
+    fn spread(&self) -> Option<f64> {
+        match (self.bid_price(), self.ask_price()) {
+            (Some(bid), Some(ask)) => Some(ask - bid),
+            _ => None,
+        }
+    }
+
+    fn vwap(&self, side: Side, size: f64) -> Option<f64> {
+        if size <= 0.0 {
+            return None;
+        }
+        let idxs: Vec<usize> = match side {
+            Side::Buy => {
+                let best = self.merged_ask();
+                self.book.range(best..).map(|(&idx, _)| idx)
+                    .chain(self.peg_book.range(best..).map(|(&idx, _)| idx))
+                    .collect::<BTreeSet<_>>().into_iter().collect()
+            }
+            Side::Sell => {
+                let best = self.merged_bid();
+                self.book.range(..=best).map(|(&idx, _)| idx)
+                    .chain(self.peg_book.range(..=best).map(|(&idx, _)| idx))
+                    .collect::<BTreeSet<_>>().into_iter().rev().collect()
             }
+        };
+
+        let mut remaining = size;
+        let mut notional = 0.0;
+        for idx in idxs {
+            if remaining <= 0.0 {
+                break;
+            }
+            let level_size = self.merged_level_volume(idx);
+            let take = remaining.min(level_size);
+            notional += take * (idx as f64 * self.tick_size);
+            remaining -= take;
+        }
+
+        if remaining > 0.0 && !relative_eq!(remaining, 0.0) {
+            None
+        } else {
+            Some(notional / size)
         }
     }
 }
 
 impl fmt::Display for OrderBook {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.bid == std::usize::MIN || self.ask == std::usize::MAX {
+        if self.merged_bid() == std::usize::MIN || self.merged_ask() == std::usize::MAX {
             return write!(f, "OB: empty");
         }
         let size = 20;
         let bids = self.bids(size).into_iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",");
         let asks = self.asks(size).into_iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",");
-        let bid = self.bid as f64 / 100.0;
-        let ask = self.ask as f64 / 100.0;
+        let bid = self.merged_bid() as f64 * self.tick_size;
+        let ask = self.merged_ask() as f64 * self.tick_size;
         write!(f, "OB: {} | {:.2}   {:.2} | {}", bids, bid, ask, asks)
     }
 }
@@ -178,130 +629,338 @@ impl fmt::Display for OrderBook {
 mod tests {
     use super::*;
 
+    fn test_config() -> OrderBookConfig {
+        OrderBookConfig {
+            tick_size: 0.01,
+            lot_size: 0.1,
+            min_size: 0.1,
+        }
+    }
+
+    fn rec(price: f64, size: f64, id: Uuid) -> BookRecord {
+        BookRecord { price, size, id, expires_at: None }
+    }
+
+    #[test]
+    fn test_validate_rejects_off_tick_price() {
+        let mut ob = OrderBook::new(test_config());
+        let err = ob.open(Side::Buy, OpenOrder::Fixed(rec(99.005, 0.1, Uuid::new_v4())), 0).unwrap_err();
+        assert!(matches!(err, Error::InvalidTick));
+
+        let err = ob.submit(
+            OrderType::Limit { id: Uuid::new_v4(), side: Side::Buy, price: 99.005, qty: 0.1 },
+            0,
+        ).unwrap_err();
+        assert!(matches!(err, Error::InvalidTick));
+    }
+
+    #[test]
+    fn test_validate_rejects_non_lot_size() {
+        let mut ob = OrderBook::new(test_config());
+        let err = ob.open(Side::Buy, OpenOrder::Fixed(rec(99.0, 0.15, Uuid::new_v4())), 0).unwrap_err();
+        assert!(matches!(err, Error::InvalidLot));
+
+        let err = ob.submit(
+            OrderType::Market { id: Uuid::new_v4(), side: Side::Buy, qty: 0.15 },
+            0,
+        ).unwrap_err();
+        assert!(matches!(err, Error::InvalidLot));
+    }
+
+    #[test]
+    fn test_validate_rejects_below_min_size() {
+        let mut ob = OrderBook::new(test_config());
+        let err = ob.open(Side::Buy, OpenOrder::Fixed(rec(99.0, 0.05, Uuid::new_v4())), 0).unwrap_err();
+        assert!(matches!(err, Error::BelowMinSize));
+
+        let err = ob.submit(
+            OrderType::Limit { id: Uuid::new_v4(), side: Side::Buy, price: 99.0, qty: 0.05 },
+            0,
+        ).unwrap_err();
+        assert!(matches!(err, Error::BelowMinSize));
+    }
+
     #[test]
     fn test_display() {
-        let mut ob = OrderBook::new();
+        let mut ob = OrderBook::new(test_config());
         ob.reload(
             vec![
-                BookRecord {
-                    price: 3994.96,
-                    size: 0.3,
-                    id: Uuid::new_v4(),
-                },
-                BookRecord {
-                    price: 3995.0,
-                    size: 0.5,
-                    id: Uuid::new_v4(),
-                },
+                rec(3994.96, 0.3, Uuid::new_v4()),
+                rec(3995.0, 0.5, Uuid::new_v4()),
             ],
             vec![
-                BookRecord {
-                    price: 4005.0,
-                    size: 0.4,
-                    id: Uuid::new_v4(),
-                },
-                BookRecord {
-                    price: 4005.02,
-                    size: 0.2,
-                    id: Uuid::new_v4(),
-                },
+                rec(4005.0, 0.4, Uuid::new_v4()),
+                rec(4005.02, 0.2, Uuid::new_v4()),
             ],
+            0,
         ).unwrap_or_default();
 
-        ob.open(
-            Side::Buy,
-            BookRecord {
-                price: 3994.96,
-                size: 0.2,
-                id: Uuid::new_v4(),
-            },
-        ).unwrap_or_default();
+        ob.open(Side::Buy, OpenOrder::Fixed(rec(3994.96, 0.2, Uuid::new_v4())), 0).unwrap_or_default();
 
         let str = format!("{}", ob);
-        assert_eq!(str, "OB: 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0.5,0,0,0,0.5 | 3995.00   4005.00 | 0.4,0,0.2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        assert_eq!(str, "OB: 0.5,0.5 | 3995.00   4005.00 | 0.4,0.2");
     }
 
     #[test]
     fn test_match() {
-        let mut ob = OrderBook::new();
+        let mut ob = OrderBook::new(test_config());
         let id1 = Uuid::new_v4();
         let id2 = Uuid::new_v4();
         ob.reload(
             vec![
-                BookRecord {
-                    price: 3994.96,
-                    size: 0.3,
-                    id: id1,
-                },
-                BookRecord {
-                    price: 3995.0,
-                    size: 0.5,
-                    id: id2,
-                },
+                rec(3994.96, 0.3, id1),
+                rec(3995.0, 0.5, id2),
             ],
             vec![
-                BookRecord {
-                    price: 4005.0,
-                    size: 0.4,
-                    id: Uuid::new_v4(),
-                },
-                BookRecord {
-                    price: 4005.02,
-                    size: 0.2,
-                    id: Uuid::new_v4(),
-                },
+                rec(4005.0, 0.4, Uuid::new_v4()),
+                rec(4005.02, 0.2, Uuid::new_v4()),
             ],
+            0,
         ).unwrap_or_default();
 
-        ob.open(
-            Side::Buy,
-            BookRecord {
-                price: 3994.96,
-                size: 0.2,
-                id: Uuid::new_v4(),
-            },
-        ).unwrap_or_default();
+        ob.open(Side::Buy, OpenOrder::Fixed(rec(3994.96, 0.2, Uuid::new_v4())), 0).unwrap_or_default();
         ob._match(3995.0, 0.5, id2).unwrap_or_default();
 
         let str = format!("{}", ob);
-        assert_eq!(str, "OB: 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0.5 | 3994.96   4005.00 | 0.4,0,0.2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        assert_eq!(str, "OB: 0.5 | 3994.96   4005.00 | 0.4,0.2");
     }
 
     #[test]
     fn test_done() {
-        let mut ob = OrderBook::new();
+        let mut ob = OrderBook::new(test_config());
         let id1 = Uuid::new_v4();
         let id2 = Uuid::new_v4();
         ob.reload(
             vec![
-                BookRecord {
-                    price: 3994.96,
-                    size: 0.3,
-                    id: id1,
-                },
-                BookRecord {
-                    price: 3995.0,
-                    size: 0.5,
-                    id: id2,
-                },
+                rec(3994.96, 0.3, id1),
+                rec(3995.0, 0.5, id2),
             ],
             vec![
-                BookRecord {
-                    price: 4005.0,
-                    size: 0.4,
-                    id: Uuid::new_v4(),
-                },
-                BookRecord {
-                    price: 4005.02,
-                    size: 0.2,
-                    id: Uuid::new_v4(),
-                },
+                rec(4005.0, 0.4, Uuid::new_v4()),
+                rec(4005.02, 0.2, Uuid::new_v4()),
             ],
+            0,
         ).unwrap_or_default();
 
-        ob.done(3994.96, id1).unwrap_or_default();
+        ob.done(3994.96, id1, 0).unwrap_or_default();
 
         let str = format!("{}", ob);
-        assert_eq!(str, "OB: 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0.5 | 3995.00   4005.00 | 0.4,0,0.2,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0");
+        assert_eq!(str, "OB: 0.5 | 3995.00   4005.00 | 0.4,0.2");
+    }
+
+    #[test]
+    fn test_submit_limit_crosses_multiple_levels() {
+        let mut ob = OrderBook::new(test_config());
+        ob.open(Side::Sell, OpenOrder::Fixed(rec(4005.0, 0.3, Uuid::new_v4())), 0).unwrap_or_default();
+        ob.open(Side::Sell, OpenOrder::Fixed(rec(4005.02, 0.2, Uuid::new_v4())), 0).unwrap_or_default();
+
+        let fills = ob.submit(
+            OrderType::Limit { id: Uuid::new_v4(), side: Side::Buy, price: 4005.02, qty: 0.4 },
+            0,
+        ).unwrap();
+
+        assert_eq!(fills.len(), 2);
+        assert!(relative_eq!(fills[0].price, 4005.0));
+        assert!(relative_eq!(fills[0].size, 0.3));
+        assert!(relative_eq!(fills[1].price, 4005.02));
+        assert!(relative_eq!(fills[1].size, 0.1));
+        assert_eq!(ob.ask_price(), Some(4005.02));
+        assert!(relative_eq!(ob.ask_volume().unwrap(), 0.1));
+    }
+
+    #[test]
+    fn test_submit_fifo_within_level() {
+        let mut ob = OrderBook::new(test_config());
+        let first_id = Uuid::new_v4();
+        let second_id = Uuid::new_v4();
+        ob.open(Side::Sell, OpenOrder::Fixed(rec(4005.0, 0.2, first_id)), 0).unwrap_or_default();
+        ob.open(Side::Sell, OpenOrder::Fixed(rec(4005.0, 0.3, second_id)), 0).unwrap_or_default();
+
+        let fills = ob.submit(
+            OrderType::Market { id: Uuid::new_v4(), side: Side::Buy, qty: 0.2 },
+            0,
+        ).unwrap();
+
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].maker_id, first_id);
+        assert_eq!(ob.ask_price(), Some(4005.0));
+        assert_eq!(ob.ask_volume(), Some(0.3));
+    }
+
+    #[test]
+    fn test_submit_limit_partial_fill_then_rests() {
+        let mut ob = OrderBook::new(test_config());
+        ob.open(Side::Sell, OpenOrder::Fixed(rec(4005.0, 0.2, Uuid::new_v4())), 0).unwrap_or_default();
+
+        let taker_id = Uuid::new_v4();
+        let fills = ob.submit(
+            OrderType::Limit { id: taker_id, side: Side::Buy, price: 4005.0, qty: 0.5 },
+            0,
+        ).unwrap();
+
+        assert_eq!(fills.len(), 1);
+        assert!(relative_eq!(fills[0].size, 0.2));
+        assert_eq!(ob.ask_price(), None);
+        assert_eq!(ob.bid_price(), Some(4005.0));
+        assert_eq!(ob.bid_volume(), Some(0.3));
+    }
+
+    #[test]
+    fn test_submit_market_unfilled_keeps_partial_fills() {
+        let mut ob = OrderBook::new(test_config());
+        let maker_id = Uuid::new_v4();
+        ob.open(Side::Sell, OpenOrder::Fixed(rec(4005.0, 0.2, maker_id)), 0).unwrap_or_default();
+
+        let err = ob.submit(
+            OrderType::Market { id: Uuid::new_v4(), side: Side::Buy, qty: 0.5 },
+            0,
+        ).unwrap_err();
+
+        let fills = match err {
+            Error::Unfilled(fills) => fills,
+            _ => panic!("expected Error::Unfilled"),
+        };
+        assert_eq!(fills.len(), 1);
+        assert!(relative_eq!(fills[0].size, 0.2));
+        assert_eq!(ob.ask_price(), None);
+    }
+
+    #[test]
+    fn test_top_of_book() {
+        let mut ob = OrderBook::new(test_config());
+        ob.reload(
+            vec![
+                rec(3994.96, 0.3, Uuid::new_v4()),
+                rec(3995.0, 0.5, Uuid::new_v4()),
+            ],
+            vec![
+                rec(4005.0, 0.4, Uuid::new_v4()),
+                rec(4005.02, 0.2, Uuid::new_v4()),
+            ],
+            0,
+        ).unwrap_or_default();
+
+        assert_eq!(ob.bid_price(), Some(3995.0));
+        assert_eq!(ob.ask_price(), Some(4005.0));
+        assert_eq!(ob.bid_volume(), Some(0.5));
+        assert_eq!(ob.ask_volume(), Some(0.4));
+        assert_eq!(ob.mid_price(), Some(4000.0));
+        assert!(relative_eq!(ob.spread().unwrap(), 10.0));
+
+        assert!(relative_eq!(ob.vwap(Side::Buy, 0.5).unwrap(), 4005.004));
+        assert!(relative_eq!(ob.vwap(Side::Sell, 0.3).unwrap(), 3995.0));
+        assert_eq!(ob.vwap(Side::Buy, 10.0), None);
+    }
+
+    #[test]
+    fn test_top_of_book_empty() {
+        let ob = OrderBook::new(test_config());
+        assert_eq!(ob.bid_price(), None);
+        assert_eq!(ob.ask_price(), None);
+        assert_eq!(ob.mid_price(), None);
+        assert_eq!(ob.spread(), None);
+        assert_eq!(ob.vwap(Side::Buy, 1.0), None);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut ob = OrderBook::new(test_config());
+        let gtc_id = Uuid::new_v4();
+        let gtd_id = Uuid::new_v4();
+
+        ob.open(Side::Buy, OpenOrder::Fixed(rec(3995.0, 0.3, gtc_id)), 100).unwrap_or_default();
+        ob.open(
+            Side::Buy,
+            OpenOrder::Fixed(BookRecord { price: 3994.0, size: 0.2, id: gtd_id, expires_at: Some(150) }),
+            100,
+        ).unwrap_or_default();
+
+        // still valid just before expiry
+        let valid: Vec<_> = ob.iter_valid(Side::Buy, 149).collect();
+        assert_eq!(valid.len(), 2);
+
+        // expired entries are filtered out of iter_valid without mutating the book
+        let valid: Vec<_> = ob.iter_valid(Side::Buy, 150).collect();
+        assert_eq!(valid, vec![(3995.0, 0.3, gtc_id)]);
+
+        // touching the expired level prunes it for good
+        ob.open(Side::Buy, OpenOrder::Fixed(rec(3994.0, 0.1, Uuid::new_v4())), 150).unwrap_or_default();
+        assert_eq!(ob.bids(10), vec![0.3, 0.1]);
+    }
+
+    #[test]
+    fn test_oracle_pegged() {
+        let mut ob = OrderBook::new(test_config());
+        let fixed_id = Uuid::new_v4();
+        let peg_id = Uuid::new_v4();
+
+        ob.open(Side::Buy, OpenOrder::Fixed(rec(99.0, 0.5, fixed_id)), 0).unwrap_or_default();
+        ob.open(
+            Side::Buy,
+            OpenOrder::Pegged { id: peg_id, size: 0.2, offset: -0.10, expires_at: None },
+            0,
+        ).unwrap_or_default();
+
+        // no oracle set yet: the pegged order has no effective price
+        assert_eq!(ob.bid_price(), Some(99.0));
+        assert_eq!(ob.bids(5), vec![0.5]);
+
+        // pegs just below the fixed bid: merged depth shows both levels
+        ob.set_oracle(98.90);
+        assert_eq!(ob.bid_price(), Some(99.0));
+        assert_eq!(ob.bids(5), vec![0.5, 0.2]);
+
+        // the oracle moves above the fixed order: the peg becomes best bid
+        ob.set_oracle(99.50);
+        assert!(relative_eq!(ob.bid_price().unwrap(), 99.40));
+        assert_eq!(ob.bids(5), vec![0.2, 0.5]);
+
+        // matching consumes the pegged order once it is the best bid
+        let fills = ob.submit(OrderType::Market { id: Uuid::new_v4(), side: Side::Sell, qty: 0.2 }, 0).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].maker_id, peg_id);
+        assert!(relative_eq!(ob.bid_price().unwrap(), 99.0));
+    }
+
+    #[test]
+    fn test_iter_valid_includes_pegged_orders() {
+        let mut ob = OrderBook::new(test_config());
+        let fixed_id = Uuid::new_v4();
+        let peg_id = Uuid::new_v4();
+
+        ob.open(Side::Buy, OpenOrder::Fixed(rec(99.0, 0.5, fixed_id)), 0).unwrap_or_default();
+        ob.open(
+            Side::Buy,
+            OpenOrder::Pegged { id: peg_id, size: 0.2, offset: 0.10, expires_at: None },
+            0,
+        ).unwrap_or_default();
+        ob.set_oracle(99.50);
+
+        // the peg (99.60) is now best bid; iter_valid must agree with bids()/bid_price()
+        let valid: Vec<_> = ob.iter_valid(Side::Buy, 0).collect();
+        assert_eq!(valid.len(), 2);
+        assert!(relative_eq!(valid[0].0, 99.60));
+        assert_eq!((valid[0].1, valid[0].2), (0.2, peg_id));
+        assert!(relative_eq!(valid[1].0, 99.0));
+        assert_eq!((valid[1].1, valid[1].2), (0.5, fixed_id));
+    }
+
+    #[test]
+    fn test_open_pegged_rejects_off_tick_projection_without_leaking_state() {
+        let mut ob = OrderBook::new(test_config());
+        ob.set_oracle(99.50);
+
+        let peg_id = Uuid::new_v4();
+        let err = ob.open(
+            Side::Buy,
+            OpenOrder::Pegged { id: peg_id, size: 0.1, offset: 0.005, expires_at: None },
+            0,
+        ).unwrap_err();
+        assert!(matches!(err, Error::InvalidTick));
+
+        // the rejected peg must not resurface once a later oracle update
+        // happens to make the same offset land on a tick boundary
+        ob.set_oracle(99.495);
+        assert_eq!(ob.bid_price(), None);
     }
 }